@@ -0,0 +1,104 @@
+//! Buffer types used to stage data between the read and write sides.
+//!
+//! `--direct` output needs buffers aligned to the logical block size, since
+//! `O_DIRECT` rejects misaligned memory; everything else is happy with a
+//! plain heap allocation. [`Buffer`] lets both live behind the same `Deref`
+//! so the rest of the pipeline doesn't need to know which one it has.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ops::{Deref, DerefMut};
+use std::slice;
+
+/// Alignment required by `O_DIRECT` on Linux; also a safe bet elsewhere.
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Smallest buffer size `auto_buf_size` will pick.
+const MIN_AUTO_BUF_SIZE: usize = 32 * 1024;
+/// Largest buffer size `auto_buf_size` will pick.
+const MAX_AUTO_BUF_SIZE: usize = 8 * 1024 * 1024;
+
+/// Pick a default buffer size from the L1 data cache size, clamped to a
+/// sane range and rounded to a page multiple. A page is far too small for
+/// efficient bulk copying; throughput is highly sensitive to how a per-op
+/// buffer size relates to cache size.
+pub fn auto_buf_size() -> usize {
+    let l1 = cache_size::l1_cache_size().unwrap_or(MIN_AUTO_BUF_SIZE);
+    let clamped = l1.clamp(MIN_AUTO_BUF_SIZE, MAX_AUTO_BUF_SIZE);
+    let page = page_size::get();
+    (clamped + page - 1) / page * page
+}
+
+/// A buffer allocated with an explicit alignment, for use with `O_DIRECT`.
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+    layout: Layout,
+}
+
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("invalid buffer size/alignment");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, len, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Either a plain heap buffer or one aligned for `O_DIRECT`.
+pub enum Buffer {
+    Heap(Vec<u8>),
+    Aligned(AlignedBuf),
+}
+
+impl Buffer {
+    pub fn new(len: usize, direct: bool) -> Self {
+        if direct {
+            Buffer::Aligned(AlignedBuf::new(len, DIRECT_IO_ALIGN))
+        } else {
+            Buffer::Heap(std::iter::repeat(0).take(len).collect())
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Buffer::Heap(v) => v,
+            Buffer::Aligned(a) => a,
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Buffer::Heap(v) => v,
+            Buffer::Aligned(a) => a,
+        }
+    }
+}
@@ -1,13 +1,34 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use multiqueue::mpmc_queue;
 use std::fs::{File, OpenOptions};
-use std::io::{self, prelude::*, BufRead, BufWriter, SeekFrom, Write};
+use std::io::{self, BufRead, Write};
 use std::num::NonZeroUsize;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use tqdm::pbar;
 
+mod buffer;
+mod mmap_engine;
+mod reader_pool;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring_engine;
+
+use buffer::{auto_buf_size, Buffer, DIRECT_IO_ALIGN};
+use mmap_engine::SharedMmap;
+
+/// Which backend drives writes to the output file.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+enum IoEngine {
+    /// A pool of threads issuing blocking positional writes (the default).
+    Sync,
+    /// A single submit/reap loop backed by `io_uring` (Linux only, requires
+    /// the `uring` feature; falls back to `sync` otherwise).
+    Uring,
+}
+
 #[derive(Debug, Parser)]
 struct Args {
     /// Number of times to repeat each input file
@@ -29,6 +50,24 @@ struct Args {
     #[arg(short, long)]
     nthreads: Option<NonZeroUsize>,
 
+    /// Size of each read/write buffer (default: the largest input file when
+    /// --max-mem-usage exceeds the total input size, otherwise auto-tuned to
+    /// the L1 cache)
+    #[arg(long)]
+    buf_size: Option<NonZeroUsize>,
+
+    /// Backend used to write the output file
+    #[arg(long, default_value = "sync")]
+    io_engine: IoEngine,
+
+    /// Open the output with O_DIRECT, bypassing the page cache (Linux only)
+    #[arg(long)]
+    direct: bool,
+
+    /// Write by memory-mapping the output file instead of issuing writes
+    #[arg(long)]
+    mmap: bool,
+
     /// Read list of input files from stdin (all position arguments written first)
     #[arg(short, long)]
     stdin_input_list: bool,
@@ -60,8 +99,13 @@ fn output_to_file(
     files: &Vec<PathBuf>,
     nthreads: NonZeroUsize,
     max_mem_usage: NonZeroUsize,
+    buf_size_arg: Option<NonZeroUsize>,
+    io_engine: IoEngine,
+    direct: bool,
+    mmap: bool,
 ) -> Result<(), io::Error> {
-    let mut files: Vec<File> = files
+    let file_paths = Arc::new(files.clone());
+    let files: Vec<File> = files
         .iter()
         .map(|f| File::open(f))
         .collect::<io::Result<Vec<_>>>()?;
@@ -74,6 +118,9 @@ fn output_to_file(
         prefix_lens.push(total_each);
         total_each += bytes_from_file;
     }
+    drop(files);
+    let file_sizes = Arc::new(file_sizes);
+    let prefix_lens_for_readers = Arc::new(prefix_lens.clone());
     prefix_lens.push(total_each);
     let total_len = total_each * usize::from(repeat_each) * usize::from(repeat_all);
 
@@ -83,102 +130,227 @@ fn output_to_file(
     drop(output);
 
     let unlimited_mem = usize::from(max_mem_usage) > *prefix_lens.last().unwrap() as usize;
-    let buf_size = if unlimited_mem {
+    let mem_usage = std::cmp::min(
+        *prefix_lens.last().unwrap() as usize,
+        usize::from(max_mem_usage),
+    );
+    let mut buf_size = if let Some(b) = buf_size_arg {
+        usize::from(b)
+    } else if unlimited_mem {
         *file_sizes.iter().max().unwrap()
     } else {
-        page_size::get()
+        auto_buf_size()
     };
+    if direct {
+        // O_DIRECT requires every write to be aligned to the logical block
+        // size, so round the buffer size up to a multiple of it. That can
+        // push buf_size past our memory budget, so clamp back down
+        // afterwards to guarantee at least one buffer fits; any write this
+        // produces that ends up misaligned is handled by the tail fallback.
+        buf_size = (buf_size + DIRECT_IO_ALIGN - 1) / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN;
+        buf_size = buf_size.min(mem_usage);
+    }
     assert!(
-        buf_size < max_mem_usage.into(),
+        buf_size > 0 && buf_size <= max_mem_usage.into(),
         "--max-mem-usage value must be at least the size of one page {}",
         buf_size
     );
 
-    let mem_usage = std::cmp::min(
-        *prefix_lens.last().unwrap() as usize,
-        usize::from(max_mem_usage),
-    );
     let n_bufs = mem_usage / buf_size;
+    assert!(
+        n_bufs >= 1,
+        "--max-mem-usage value is too small to fit a single {}-byte buffer",
+        buf_size
+    );
     let (buffer_free_notify, buffer_free_events) =
         mpmc_queue((n_bufs * usize::from(repeat_each)) as u64);
     let queue_depth = usize::from(nthreads) as u64 * 2;
-    let (task_sender, task_recvr) = mpmc_queue::<(u64, usize, Arc<Vec<u8>>)>(queue_depth);
+    let (task_sender, task_recvr) = mpmc_queue::<(u64, usize, usize, Arc<Buffer>)>(queue_depth);
 
-    let mut buf_alloc_counts: Vec<usize> = Vec::new();
+    let mut buf_alloc_counts: Vec<AtomicUsize> = Vec::new();
     for i in 0..n_bufs {
-        // Initialize every buffer as ready to be written
-        buf_alloc_counts.push(1);
+        // Initialize every buffer as ready to be filled
+        buf_alloc_counts.push(AtomicUsize::new(1));
         while let Err(_) = buffer_free_notify.try_send(i) {}
     }
+    let buf_alloc_counts = Arc::new(buf_alloc_counts);
+
+    // Split the input into fixed-size chunks up front so a pool of reader
+    // threads can pull them independently instead of one thread reading
+    // every file serially.
+    let chunks = reader_pool::plan_chunks(&file_sizes, buf_size);
+    let (chunk_sender, chunk_recvr) = mpmc_queue::<reader_pool::Chunk>(chunks.len() as u64);
+    for chunk in chunks {
+        while let Err(_) = chunk_sender.try_send(chunk) {}
+    }
+    chunk_sender.unsubscribe();
 
     let pbar = Arc::new(Mutex::new(pbar(Some(total_len))));
 
-    let mut children: Vec<JoinHandle<Result<(), io::Error>>> = vec![];
-    for _ in 0..nthreads.into() {
-        let buffer_free_notify = buffer_free_notify.clone();
-        let task_recvr = task_recvr.clone();
-        let output = OpenOptions::new().write(true).open(&output_file)?;
-        let mut output = BufWriter::new(output);
-        let pbar = pbar.clone();
-        children.push(thread::spawn(move || {
-            for (offset, buf_id, buffer) in task_recvr {
-                output.seek(SeekFrom::Start(offset))?;
-                output.write_all(&buffer)?;
-                let _ = pbar.lock().unwrap().update(buffer.len());
-                while let Err(_) = buffer_free_notify.try_send(buf_id) {}
-            }
+    let mmap = if mmap {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&output_file)?;
+        Some(Arc::new(SharedMmap::new(unsafe {
+            memmap2::MmapMut::map_mut(&file)?
+        })))
+    } else {
+        None
+    };
 
-            Ok(())
-        }));
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    let use_uring = io_engine == IoEngine::Uring && {
+        // `IoUring::new` is also what fails when the running kernel lacks
+        // uring support; probe it here, before any writer threads are
+        // spawned, so that case can still fall back to the sync engine
+        // instead of aborting the whole run.
+        let supported = uring_engine::is_supported(queue_depth as u32);
+        if !supported {
+            eprintln!("io_uring is not supported on this kernel; falling back to sync");
+        }
+        supported
+    };
+    #[cfg(not(all(target_os = "linux", feature = "uring")))]
+    let use_uring = {
+        if io_engine == IoEngine::Uring {
+            eprintln!("--io-engine uring requires Linux and the `uring` feature; falling back to sync");
+        }
+        false
+    };
+
+    if mmap.is_some() && (direct || use_uring) {
+        // --mmap takes over the whole write path below, so --direct/--io-engine
+        // uring would otherwise be silently ignored; say so.
+        eprintln!("--mmap takes precedence over --direct and --io-engine uring; those flags are being ignored");
     }
-    buffer_free_notify.unsubscribe();
-    task_recvr.unsubscribe();
 
-    let mut total_written = 0;
-    for buf_id in &buffer_free_events {
-        buf_alloc_counts[buf_id] -= 1;
-        if buf_alloc_counts[buf_id] == 0 {
-            let mut buf: Vec<u8> = std::iter::repeat(0).take(buf_size).collect();
-            for fid in 0..files.len() {
-                let f = &mut files[fid];
-                let foffset = f.seek(SeekFrom::Current(0))?;
-                let nbytes = f.read(&mut buf)?;
-                if nbytes == 0 {
-                    continue;
-                }
+    let mut children: Vec<JoinHandle<Result<(), io::Error>>> = vec![];
+    if let Some(mmap) = &mmap {
+        for _ in 0..nthreads.into() {
+            let buffer_free_notify = buffer_free_notify.clone();
+            let task_recvr = task_recvr.clone();
+            let mmap = mmap.clone();
+            let pbar = pbar.clone();
+            children.push(thread::spawn(move || {
+                mmap_engine::run(mmap, task_recvr, buffer_free_notify, pbar)
+            }));
+        }
+    } else {
+        // Positional writes (pwrite) don't touch the file cursor, so a single
+        // handle can be shared across all writer threads: each task's offset
+        // is disjoint, making the concurrent writes well-defined without
+        // per-thread opens or a BufWriter (which would be unsound to seek
+        // mid-buffer).
+        let mut output_opts = OpenOptions::new();
+        output_opts.write(true);
+        #[cfg(target_os = "linux")]
+        if direct {
+            output_opts.custom_flags(libc::O_DIRECT);
+        }
+        let output = Arc::new(output_opts.open(&output_file)?);
+        // O_DIRECT can't write a block-misaligned length or offset (such as
+        // the final, less-than-a-block tail of the file); those writes go
+        // through a plain handle instead.
+        let tail_output = Arc::new(OpenOptions::new().write(true).open(&output_file)?);
 
-                let buf = Arc::new(buf);
-                buf_alloc_counts[buf_id] = usize::from(repeat_each) * usize::from(repeat_all);
-                for i in 0..usize::from(repeat_all) {
-                    let mut offset = total_each * i + prefix_lens[fid] * usize::from(repeat_each);
-                    for _ in 0..usize::from(repeat_each) {
-                        while let Err(_) =
-                            task_sender.try_send((offset as u64 + foffset, buf_id, buf.clone()))
+        if use_uring {
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            {
+                let buffer_free_notify = buffer_free_notify.clone();
+                let task_recvr = task_recvr.clone();
+                let output = output.clone();
+                let tail_output = tail_output.clone();
+                let pbar = pbar.clone();
+                children.push(thread::spawn(move || {
+                    uring_engine::run(
+                        &output,
+                        &tail_output,
+                        direct,
+                        queue_depth,
+                        task_recvr,
+                        buffer_free_notify,
+                        pbar,
+                    )
+                }));
+            }
+        } else {
+            for _ in 0..nthreads.into() {
+                let buffer_free_notify = buffer_free_notify.clone();
+                let task_recvr = task_recvr.clone();
+                let output = output.clone();
+                let tail_output = tail_output.clone();
+                let pbar = pbar.clone();
+                children.push(thread::spawn(move || {
+                    for (offset, buf_id, len, buffer) in task_recvr {
+                        // O_DIRECT requires both the length and the file
+                        // offset of every write to be block-aligned; route
+                        // anything that isn't through the plain handle
+                        // instead. Offsets land on file/repeat boundaries
+                        // that aren't multiples of the block size whenever
+                        // an input file's size isn't, so this isn't just the
+                        // final tail write.
+                        if direct
+                            && (len % DIRECT_IO_ALIGN != 0
+                                || offset % DIRECT_IO_ALIGN as u64 != 0)
                         {
+                            tail_output.write_all_at(&buffer[..len], offset)?;
+                        } else {
+                            output.write_all_at(&buffer[..len], offset)?;
+                        }
+                        let _ = pbar.lock().unwrap().update(len);
+                        while let Err(_) = buffer_free_notify.try_send(buf_id) {
+                            std::thread::yield_now();
                         }
-                        offset += file_sizes[fid];
-                        total_written += buf.len();
                     }
-                }
 
-                break;
+                    Ok(())
+                }));
             }
         }
+    }
+    buffer_free_notify.unsubscribe();
+    task_recvr.unsubscribe();
 
-        if total_written == total_len {
-            break;
-        }
+    let mut readers: Vec<JoinHandle<Result<(), io::Error>>> = vec![];
+    for _ in 0..nthreads.into() {
+        let file_paths = file_paths.clone();
+        let file_sizes = file_sizes.clone();
+        let prefix_lens_for_readers = prefix_lens_for_readers.clone();
+        let buf_alloc_counts = buf_alloc_counts.clone();
+        let buffer_free_events = buffer_free_events.clone();
+        let chunk_recvr = chunk_recvr.clone();
+        let task_sender = task_sender.clone();
+        readers.push(thread::spawn(move || {
+            reader_pool::run(
+                file_paths,
+                file_sizes,
+                prefix_lens_for_readers,
+                total_each,
+                repeat_each,
+                repeat_all,
+                direct,
+                buf_alloc_counts,
+                buffer_free_events,
+                chunk_recvr,
+                task_sender,
+            )
+        }));
+    }
+    chunk_recvr.unsubscribe();
+    task_sender.unsubscribe();
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
     }
 
     let mut n_outstanding_buffers = buf_alloc_counts
         .iter()
-        .fold(0, |acc, e| acc + if *e > 0 { 1 } else { 0 });
+        .fold(0, |acc, e| acc + if e.load(Ordering::Acquire) > 0 { 1 } else { 0 });
     let evt_iter = &mut buffer_free_events.into_iter();
     while n_outstanding_buffers > 0 {
         match evt_iter.next() {
             Some(buf_id) => {
-                buf_alloc_counts[buf_id] -= 1;
-                if buf_alloc_counts[buf_id] == 0 {
+                if buf_alloc_counts[buf_id].fetch_sub(1, Ordering::AcqRel) == 1 {
                     n_outstanding_buffers -= 1
                 }
 
@@ -189,6 +361,10 @@ fn output_to_file(
             None => break,
         }
     }
+
+    if let Some(mmap) = &mmap {
+        mmap.flush()?;
+    }
     Ok(())
 }
 
@@ -222,6 +398,10 @@ fn main() -> Result<(), std::io::Error> {
             },
             args.max_mem_usage
                 .unwrap_or(NonZeroUsize::new(usize::max_value()).unwrap()),
+            args.buf_size,
+            args.io_engine,
+            args.direct,
+            args.mmap,
         ),
         None => output_to_stdout(args.repeat_each, args.repeat_all, &args.files),
     }
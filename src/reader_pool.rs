@@ -0,0 +1,120 @@
+//! Parallel read scheduling for the output-to-file path.
+//!
+//! Splits every input file into fixed-size chunks up front and hands them
+//! out to a pool of reader threads instead of reading each file serially on
+//! the main thread. Each reader opens its own handle, `read_at`s its chunk,
+//! and replicates the resulting buffer across `repeat_each`/`repeat_all`
+//! exactly as the single-threaded reader used to, dispatching write tasks
+//! through the same `task_sender`/`buffer_free_notify` bookkeeping. This
+//! decouples read concurrency from write concurrency.
+
+use multiqueue::{MPMCReceiver, MPMCSender};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::buffer::Buffer;
+
+/// A `(file index, start offset, length)` slice of an input file.
+pub type Chunk = (usize, u64, usize);
+
+/// Split every input file into `buf_size`-sized chunks, in file order.
+pub fn plan_chunks(file_sizes: &[usize], buf_size: usize) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    for (fid, &size) in file_sizes.iter().enumerate() {
+        let mut start = 0usize;
+        while start < size {
+            let len = std::cmp::min(buf_size, size - start);
+            chunks.push((fid, start as u64, len));
+            start += len;
+        }
+    }
+    chunks
+}
+
+/// Drain `chunk_recvr`, reading one chunk per free buffer slot and
+/// enqueueing the replicated writes onto `task_sender`. Returns once there
+/// are no chunks left to read.
+///
+/// Exhaustion is driven by `chunk_recvr` itself rather than by counting
+/// `buffer_free_events`: `chunk_recvr.recv()` deterministically returns an
+/// error once every chunk has been claimed and `chunk_sender` has been
+/// unsubscribed, regardless of which reader thread happens to win any given
+/// race for an event. Inferring exhaustion from event delivery order instead
+/// let some reader threads, on a box with more reader threads than
+/// recycling events left near the end of a run, never see a reason to stop
+/// and block on `buffer_free_events` forever — which in turn wedged the
+/// writers and the drain loop in `main`.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    paths: Arc<Vec<PathBuf>>,
+    file_sizes: Arc<Vec<usize>>,
+    prefix_lens: Arc<Vec<usize>>,
+    total_each: usize,
+    repeat_each: NonZeroUsize,
+    repeat_all: NonZeroUsize,
+    direct: bool,
+    buf_alloc_counts: Arc<Vec<AtomicUsize>>,
+    buffer_free_events: MPMCReceiver<usize>,
+    chunk_recvr: MPMCReceiver<Chunk>,
+    task_sender: MPMCSender<(u64, usize, usize, Arc<Buffer>)>,
+) -> io::Result<()> {
+    // One handle per input file, opened the first time this reader touches
+    // it and reused for every later chunk instead of reopening per chunk.
+    let mut open_files: HashMap<usize, File> = HashMap::new();
+
+    while let Ok((fid, start, len)) = chunk_recvr.recv() {
+        // Wait for a buffer slot whose previous generation has fully
+        // drained; only the event that brings a slot's refcount to zero
+        // means every write still reading it has finished.
+        let buf_id = loop {
+            let buf_id = buffer_free_events.recv().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "buffer_free_events closed while a chunk was still waiting for a slot",
+                )
+            })?;
+            if buf_alloc_counts[buf_id].fetch_sub(1, Ordering::AcqRel) == 1 {
+                break buf_id;
+            }
+        };
+
+        let file = match open_files.get(&fid) {
+            Some(file) => file,
+            None => {
+                open_files.insert(fid, File::open(&paths[fid])?);
+                &open_files[&fid]
+            }
+        };
+        let mut buf = Buffer::new(len, direct);
+        file.read_at(&mut buf, start)?;
+        let buf = Arc::new(buf);
+
+        buf_alloc_counts[buf_id].store(
+            usize::from(repeat_each) * usize::from(repeat_all),
+            Ordering::Release,
+        );
+        for i in 0..usize::from(repeat_all) {
+            let mut offset =
+                total_each * i + prefix_lens[fid] * usize::from(repeat_each) + start as usize;
+            for _ in 0..usize::from(repeat_each) {
+                // `MPMCSender` has no blocking send; yield between spins so
+                // a full queue doesn't have every reader thread burning CPU
+                // the writer threads need to drain it.
+                while let Err(_) =
+                    task_sender.try_send((offset as u64, buf_id, len, buf.clone()))
+                {
+                    std::thread::yield_now();
+                }
+                offset += file_sizes[fid];
+            }
+        }
+    }
+
+    Ok(())
+}
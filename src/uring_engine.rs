@@ -0,0 +1,139 @@
+//! io_uring-backed output engine.
+//!
+//! Instead of a pool of threads each blocking on `write_all_at`, this engine
+//! runs a single submit/reap loop: writes are pushed onto an `io_uring`
+//! submission queue and their completions are reaped to recycle buffers,
+//! keeping at most `queue_depth` writes in flight at once. This cuts
+//! per-write syscall overhead dramatically for workloads with many small
+//! buffers.
+
+use multiqueue::{MPMCReceiver, MPMCSender};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use tqdm::Pbar;
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::buffer::{Buffer, DIRECT_IO_ALIGN};
+
+/// Probe whether the running kernel supports `io_uring` at the given queue
+/// depth. Callers use this to decide between the uring and sync write paths
+/// before any writer threads are spawned, since `IoUring::new` is also the
+/// call that fails on a kernel built or configured without uring support.
+pub fn is_supported(queue_depth: u32) -> bool {
+    IoUring::new(queue_depth).is_ok()
+}
+
+/// Drain `task_recvr` through an `io_uring` instance sized to `queue_depth`,
+/// writing each `(offset, buf_id, len, buffer)` task at its offset and
+/// returning `buf_id` to `buffer_free_notify` once the write completes.
+///
+/// `output` is opened `O_DIRECT` when `direct` is set; exactly as the sync
+/// engine does, any write whose length or offset isn't block-aligned is
+/// routed to the plain `tail_output` handle instead, since `O_DIRECT`
+/// rejects misaligned writes outright.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    output: &File,
+    tail_output: &File,
+    direct: bool,
+    queue_depth: u64,
+    task_recvr: MPMCReceiver<(u64, usize, usize, Arc<Buffer>)>,
+    buffer_free_notify: MPMCSender<usize>,
+    pbar: Arc<Mutex<Pbar>>,
+) -> io::Result<()> {
+    let mut ring = IoUring::new(queue_depth as u32)?;
+    let fd = types::Fd(output.as_raw_fd());
+    let tail_fd = types::Fd(tail_output.as_raw_fd());
+
+    // Keyed by the submission's `user_data`; carries the write's own fd and
+    // offset (distinct from the task's original offset once a short write
+    // has been resubmitted) alongside the buffer, which must stay alive
+    // until reaped.
+    let mut in_flight: HashMap<u64, (types::Fd, u64, usize, usize, Arc<Buffer>)> = HashMap::new();
+    let mut next_id: u64 = 0;
+    let mut tasks = task_recvr.into_iter();
+    let mut no_more_tasks = false;
+
+    while !no_more_tasks || !in_flight.is_empty() {
+        while !no_more_tasks && (in_flight.len() as u64) < queue_depth {
+            match tasks.next() {
+                Some((offset, buf_id, len, buffer)) => {
+                    let target_fd = if direct
+                        && (len % DIRECT_IO_ALIGN != 0 || offset % DIRECT_IO_ALIGN as u64 != 0)
+                    {
+                        tail_fd
+                    } else {
+                        fd
+                    };
+                    let entry = opcode::Write::new(target_fd, buffer.as_ptr(), len as u32)
+                        .offset(offset)
+                        .build()
+                        .user_data(next_id);
+                    // Safety: `buffer` is kept alive in `in_flight` until its
+                    // completion is reaped below, so the kernel always writes
+                    // through a valid pointer.
+                    unsafe {
+                        ring.submission()
+                            .push(&entry)
+                            .expect("io_uring submission queue unexpectedly full");
+                    }
+                    in_flight.insert(next_id, (target_fd, offset, buf_id, len, buffer));
+                    next_id += 1;
+                }
+                None => no_more_tasks = true,
+            }
+        }
+
+        if in_flight.is_empty() {
+            break;
+        }
+
+        ring.submit_and_wait(1)?;
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            let (target_fd, offset, buf_id, len, buffer) = in_flight
+                .remove(&user_data)
+                .expect("completion for unknown write");
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            let written = result as usize;
+            let _ = pbar.lock().unwrap().update(written);
+            if written < len {
+                // A regular-file write can complete short (signal
+                // interruption, disk pressure); resubmit the unwritten tail
+                // at the advanced offset instead of dropping it.
+                let remaining = len - written;
+                let new_offset = offset + written as u64;
+                let entry = opcode::Write::new(
+                    target_fd,
+                    unsafe { buffer.as_ptr().add(written) },
+                    remaining as u32,
+                )
+                .offset(new_offset)
+                .build()
+                .user_data(next_id);
+                unsafe {
+                    ring.submission()
+                        .push(&entry)
+                        .expect("io_uring submission queue unexpectedly full");
+                }
+                in_flight.insert(next_id, (target_fd, new_offset, buf_id, remaining, buffer));
+                next_id += 1;
+            } else {
+                while let Err(_) = buffer_free_notify.try_send(buf_id) {
+                    std::thread::yield_now();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}